@@ -0,0 +1,73 @@
+use std::io;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::process::ChildStdin;
+use tokio::sync::{watch, Mutex};
+use tokio::task;
+
+/// The currently-running child's stdin, or `None` while the supervisor is
+/// between restarts and no child is running. Shared with `input_task` so
+/// both always write to whichever child is actually alive right now.
+pub type SharedStdin = watch::Receiver<Option<Arc<Mutex<ChildStdin>>>>;
+
+/// Binds the control socket for `--control-socket`. Split out from
+/// `spawn_control_socket` so the caller can report a bind failure as a
+/// normal error instead of it surfacing from inside a spawned task.
+pub fn bind_control_socket(path: &str) -> io::Result<UnixListener> {
+  // Remove a stale socket left behind by a previous run, otherwise
+  // bind() fails with "address already in use"
+  let _ = std::fs::remove_file(path);
+
+  UnixListener::bind(path)
+}
+
+/// Spawns the control-socket accept loop. Each connection is treated like
+/// another copy of the wrapper's own stdin - every line it sends goes to
+/// the child's stdin - so admin clients can send commands (save, reload,
+/// ...) at runtime, e.g. `echo save | socat - UNIX-CONNECT:/run/wrapper.sock`.
+pub fn spawn_control_socket(listener: UnixListener, child_stdin: SharedStdin) {
+  task::spawn(async move {
+    loop {
+      let (stream, _addr) = match listener.accept().await {
+        Ok(conn) => conn,
+        Err(e) => {
+          eprintln!("WARNING: control socket accept() failed: {}", e);
+          continue;
+        },
+      };
+
+      task::spawn(handle_connection(stream, child_stdin.clone()));
+    }
+  });
+}
+
+/// Forwards lines from one control-socket connection into the current
+/// child's stdin. Unlike `input_task`, a write error here only drops this
+/// connection, not the whole wrapper.
+async fn handle_connection(stream: UnixStream, mut child_stdin: SharedStdin) {
+  let mut lines = BufReader::new(stream).lines();
+
+  loop {
+    let mut line = match lines.next_line().await {
+      Ok(Some(l)) => l,
+      Ok(None) => return,
+      Err(e) => {
+        eprintln!("WARNING: control socket connection read error: {}", e);
+        return;
+      },
+    };
+    line.push('\n');
+
+    let stdin = child_stdin.borrow_and_update().clone();
+    match stdin {
+      Some(stdin) => {
+        if let Err(e) = stdin.lock().await.write_all(line.as_bytes()).await {
+          eprintln!("WARNING: unable to write control-socket command to child process: {}", e);
+          return;
+        }
+      },
+      None => eprintln!("WARNING: dropping control-socket command, no child is currently running"),
+    }
+  }
+}
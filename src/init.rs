@@ -0,0 +1,89 @@
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::task;
+
+/// A handle to the PID-1 reaper task spawned by `--init`. Lives for the
+/// whole wrapper lifetime (not per-child) since it's the only thing
+/// allowed to `waitpid()` our children; `tracked_pid` is updated in place
+/// before each respawn, and `exits` delivers every reaped `(pid, status)`,
+/// tagged rather than pre-filtered - see `wait_for_tracked_exit`.
+pub struct Reaper {
+  pub tracked_pid: Arc<AtomicU32>,
+  pub exits: mpsc::UnboundedReceiver<(u32, ExitStatus)>,
+}
+
+/// Spawns the PID-1 reaper task used by `--init`: installs a `SIGCHLD`
+/// handler and drains every exited child with `waitpid(-1, WNOHANG)` each
+/// time it fires, so re-parented orphans don't pile up as zombies.
+pub fn spawn() -> Reaper {
+  let tracked_pid = Arc::new(AtomicU32::new(0));
+  let (tx, rx) = mpsc::unbounded_channel();
+
+  task::spawn(async move {
+    let mut sigchld = signal(SignalKind::child()).expect("stream error");
+
+    loop {
+      sigchld.recv().await;
+      reap_all(&tx);
+    }
+  });
+
+  Reaper { tracked_pid, exits: rx }
+}
+
+/// Waits for the exit status of whichever pid is currently in `tracked_pid`,
+/// discarding entries for any other pid (orphans, or a previous tracked
+/// child) until the target turns up. Filtering happens here rather than in
+/// `reap_all` because a child can crash and get reaped before its pid is
+/// even stored into `tracked_pid` - pre-filtering there would drop that
+/// exit as an "orphan" and hang this forever.
+pub async fn wait_for_tracked_exit(reaper: &mut Reaper) -> Option<ExitStatus> {
+  let target = reaper.tracked_pid.load(Ordering::SeqCst);
+
+  while let Some((pid, status)) = reaper.exits.recv().await {
+    if pid == target {
+      return Some(status);
+    }
+  }
+
+  None
+}
+
+/// Reaps every child that can be reaped without blocking, forwarding the
+/// pid and status of each one - tracked or orphan alike - for
+/// `wait_for_tracked_exit` to filter.
+fn reap_all(tx: &mpsc::UnboundedSender<(u32, ExitStatus)>) {
+  loop {
+    match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
+      Ok(WaitStatus::StillAlive) => break,
+      Ok(status) => {
+        if let Some((pid, exit_status)) = decode(status) {
+          let _ = tx.send((pid.as_raw() as u32, exit_status));
+        }
+      },
+      Err(nix::errno::Errno::ECHILD) => break,
+      Err(e) => {
+        eprintln!("WARNING: waitpid failed while reaping children: {}", e);
+        break;
+      },
+    }
+  }
+}
+
+/// Turns a `WaitStatus` for a terminated process into its pid and the
+/// equivalent `std::process::ExitStatus`. Returns `None` for statuses that
+/// don't represent a child actually exiting (e.g. `Stopped`/`Continued`),
+/// which we don't care about here.
+fn decode(status: WaitStatus) -> Option<(Pid, ExitStatus)> {
+  match status {
+    WaitStatus::Exited(pid, code) => Some((pid, ExitStatus::from_raw(code << 8))),
+    WaitStatus::Signaled(pid, signal, _) => Some((pid, ExitStatus::from_raw(signal as i32))),
+    _ => None,
+  }
+}
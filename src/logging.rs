@@ -0,0 +1,133 @@
+use chrono::Utc;
+use std::fs::OpenOptions;
+use std::io;
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::process::{ChildStderr, ChildStdout};
+use tokio::sync::Mutex;
+use tokio::task;
+
+/// How child output lines are formatted before being printed (and
+/// optionally teed to a file). Default is `Raw`, which matches the old
+/// behaviour of just letting the lines through unmodified.
+#[derive(Clone, Copy)]
+pub enum LogFormat {
+  Raw,
+  /// `--log-prefix`: `<ISO-8601 timestamp> <stream>: <line>`.
+  Prefixed,
+  /// `--json-logs`: one `{"ts","stream","line"}` object per line.
+  Json,
+}
+
+/// Opens `--log-file` once for the whole wrapper lifetime, in append mode,
+/// so it's reused across every respawn instead of being truncated each time.
+pub fn open_tee(path: &str) -> io::Result<Arc<Mutex<File>>> {
+  let file = OpenOptions::new().create(true).append(true).open(path)?;
+  Ok(Arc::new(Mutex::new(File::from_std(file))))
+}
+
+/// Spawns the reader tasks that tag, timestamp and optionally tee a
+/// child's stdout/stderr.
+pub fn spawn_output_tasks(stdout: ChildStdout, stderr: ChildStderr, format: LogFormat, tee: Option<Arc<Mutex<File>>>) {
+  task::spawn(read_stream(stdout, "stdout", format, tee.clone()));
+  task::spawn(read_stream(stderr, "stderr", format, tee));
+}
+
+// Read raw bytes up to each '\n', not BufReader::lines() - lines() drops
+// the whole stream on the first non-UTF-8 byte. Decoded lossily instead.
+async fn read_stream<R: AsyncRead + Unpin>(stream: R, name: &'static str, format: LogFormat, tee: Option<Arc<Mutex<File>>>) {
+  let mut reader = BufReader::new(stream);
+  let mut buf = Vec::new();
+
+  loop {
+    buf.clear();
+    match reader.read_until(b'\n', &mut buf).await {
+      Ok(0) => return,
+      Ok(_) => {},
+      Err(e) => {
+        eprintln!("WARNING: error reading child {}: {}", name, e);
+        return;
+      },
+    }
+
+    if buf.last() == Some(&b'\n') {
+      buf.pop();
+      if buf.last() == Some(&b'\r') {
+        buf.pop();
+      }
+    }
+
+    let line = String::from_utf8_lossy(&buf);
+    let formatted = format_line(name, &line, format);
+
+    // Raw mode has no stream tag, so stderr must stay on stderr to keep
+    // Docker log drivers' fd1/fd2 separation; Prefixed/Json both tag the
+    // stream and go to stdout.
+    match (format, name) {
+      (LogFormat::Raw, "stderr") => eprintln!("{}", formatted),
+      _ => println!("{}", formatted),
+    }
+
+    if let Some(tee) = &tee {
+      let mut tee = tee.lock().await;
+      if let Err(e) = tee.write_all(format!("{}\n", formatted).as_bytes()).await {
+        eprintln!("WARNING: unable to write to log file: {}", e);
+      }
+    }
+  }
+}
+
+fn format_line(stream: &str, line: &str, format: LogFormat) -> String {
+  match format {
+    LogFormat::Raw => line.to_string(),
+    LogFormat::Prefixed => format!("{} {}: {}", Utc::now().to_rfc3339(), stream, line),
+    LogFormat::Json => format!(
+      "{{\"ts\":\"{}\",\"stream\":\"{}\",\"line\":\"{}\"}}",
+      Utc::now().to_rfc3339(), stream, json_escape(line),
+    ),
+  }
+}
+
+/// Minimal JSON string escaping - the wrapper has no other use for a JSON
+/// library, so it's not worth pulling in serde_json for this one field.
+fn json_escape(s: &str) -> String {
+  let mut out = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      '\r' => out.push_str("\\r"),
+      '\t' => out.push_str("\\t"),
+      c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c),
+    }
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn json_escape_passes_through_plain_text() {
+    assert_eq!(json_escape("hello world"), "hello world");
+  }
+
+  #[test]
+  fn json_escape_escapes_quotes_and_backslashes() {
+    assert_eq!(json_escape(r#"say "hi"\now"#), r#"say \"hi\"\\now"#);
+  }
+
+  #[test]
+  fn json_escape_escapes_common_whitespace() {
+    assert_eq!(json_escape("a\nb\rc\td"), "a\\nb\\rc\\td");
+  }
+
+  #[test]
+  fn json_escape_escapes_other_control_characters() {
+    assert_eq!(json_escape("\u{1}\u{1f}"), "\\u0001\\u001f");
+  }
+}
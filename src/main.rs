@@ -1,41 +1,13 @@
-use tokio::{io, task, process};
-use tokio::sync::Mutex;
-use tokio::prelude::*;
-use tokio::signal::unix::{signal, SignalKind};
-use std::process::{Stdio, exit};
-use std::sync::Arc;
+use std::process::exit;
 use clap::{App, Arg};
-use tokio::io::AsyncBufReadExt;
-
-async fn input_task(child_stdin: Arc<Mutex<process::ChildStdin>>) {
-  let mut lines = io::BufReader::new(io::stdin()).lines();
-
-  loop {
-    let line = lines.next_line().await;
-
-    let line = match line {
-        Ok(l) => l,
-        Err(e) => {
-            eprintln!("WARNING: can't read from stdin: {}", e);
-            return;
-        },
-    };
-
-    let mut line = match line {
-        Some(l) => l,
-        None => {
-            eprintln!("WARNING: can't read from stdin: closed");
-            return;
-        },
-    };
-    line.push('\n');
-
-    child_stdin.lock().await.write_all(&line.into_bytes()).await.unwrap_or_else(|e| {
-      eprintln!("Unable to write to child process: {}", e);
-      exit(1);
-    });
-  }
-}
+
+mod control;
+mod init;
+mod logging;
+mod signals;
+mod supervisor;
+
+use supervisor::{RestartMode, RunOpts};
 
 #[tokio::main]
 async fn main() {
@@ -78,6 +50,73 @@ async fn main() {
       .requires("kill-command")
     )
 
+    // Init / zombie reaping
+    .arg(Arg::with_name("init")
+      .short("i")
+      .long("init")
+      .help("Act as a minimal init (like tini/docker --init) and reap orphaned zombie processes; use this when running as the container's PID 1")
+      .takes_value(false)
+    )
+
+    // Output logging
+    .arg(Arg::with_name("log-prefix")
+      .long("log-prefix")
+      .help("Prefix each line of child stdout/stderr with an ISO-8601 timestamp and a stdout/stderr tag")
+      .takes_value(false)
+      .conflicts_with("json-logs")
+    )
+    .arg(Arg::with_name("json-logs")
+      .long("json-logs")
+      .help("Emit each line of child stdout/stderr as a JSON object: {\"ts\",\"stream\",\"line\"}")
+      .takes_value(false)
+      .conflicts_with("log-prefix")
+    )
+    .arg(Arg::with_name("log-file")
+      .long("log-file")
+      .help("Additionally tee formatted child stdout/stderr lines to this file")
+      .takes_value(true)
+    )
+
+    // Control socket
+    .arg(Arg::with_name("control-socket")
+      .short("c")
+      .long("control-socket")
+      .help("Path to a Unix socket to bind; lines sent by any connected client are forwarded to the child's stdin, same as this wrapper's own stdin")
+      .takes_value(true)
+    )
+
+    // Shutdown timeout
+    .arg(Arg::with_name("shutdown-timeout")
+      .short("t")
+      .long("shutdown-timeout")
+      .help("Seconds to wait for the child to exit after the kill-command before escalating to SIGTERM and then SIGKILL")
+      .takes_value(true)
+    )
+
+    // Restart supervision
+    .arg(Arg::with_name("restart")
+      .short("r")
+      .long("restart")
+      .help("Respawn the child when it exits on its own: 'on-failure' (non-zero exit) or 'always'")
+      .takes_value(true)
+      .possible_values(&["on-failure", "always"])
+    )
+    .arg(Arg::with_name("max-restarts")
+      .long("max-restarts")
+      .help("Give up restarting after this many attempts (default: retry forever)")
+      .takes_value(true)
+      .requires("restart")
+    )
+
+    // Signal forwarding
+    .arg(Arg::with_name("forward-signal")
+      .long("forward-signal")
+      .help("Map a signal to an action: <sig>=forward (send the same signal to the child), <sig>=shutdown (clean shutdown like SIGTERM), or <sig>=<command> (send <command> to the child's stdin). May be given multiple times. TERM can't be remapped; it's always a clean shutdown.")
+      .takes_value(true)
+      .multiple(true)
+      .number_of_values(1)
+    )
+
     // The actual command
     .arg(Arg::with_name("binary + params")
       .multiple(true)
@@ -90,9 +129,44 @@ async fn main() {
 
   // Get the commandline arguments
   let debug = matches.is_present("debug");
+  let init = matches.is_present("init");
   let kill_command = matches.value_of("kill-command");
+  let control_socket = matches.value_of("control-socket");
+  let log_format = if matches.is_present("json-logs") {
+    logging::LogFormat::Json
+  } else if matches.is_present("log-prefix") {
+    logging::LogFormat::Prefixed
+  } else {
+    logging::LogFormat::Raw
+  };
+  let log_file = matches.value_of("log-file").map(|s| s.to_string());
   let newline_before_kill = !matches.is_present("no-newline-before-kill");
   let newline_after_kill = !matches.is_present("no-newline-after-kill");
+  let shutdown_timeout: Option<u64> = matches.value_of("shutdown-timeout").map(|s| {
+    s.parse().unwrap_or_else(|e| {
+      eprintln!("Invalid --shutdown-timeout value '{}': {}", s, e);
+      exit(1);
+    })
+  });
+  let restart = matches.value_of("restart").map(|s| match s {
+    "always" => RestartMode::Always,
+    _ => RestartMode::OnFailure,
+  });
+  let max_restarts: Option<u32> = matches.value_of("max-restarts").map(|s| {
+    s.parse().unwrap_or_else(|e| {
+      eprintln!("Invalid --max-restarts value '{}': {}", s, e);
+      exit(1);
+    })
+  });
+  let forward_signals = match matches.values_of("forward-signal") {
+    Some(values) => values.map(|spec| {
+      signals::parse_rule(spec).unwrap_or_else(|e| {
+        eprintln!("Invalid --forward-signal value '{}': {}", spec, e);
+        exit(1);
+      })
+    }).collect(),
+    None => Vec::new(),
+  };
 
   // Pull out the binary and parameters as an iterator (ignore errors, since the
   // library handles them)
@@ -108,68 +182,25 @@ async fn main() {
   // Collect up the arguments, if any
   let binary_args: Vec<&str> = binary_args.collect();
 
-  if debug {
-    eprintln!("Running command: {}", binary)
-  }
-
-  // Spawn a child process
-  let mut child = process::Command::new(binary).args(binary_args).stdin(Stdio::piped()).spawn().unwrap_or_else(|e| {
-    eprintln!("Error creating process: {}", e);
-    exit(1);
-  });
-
-  // Get the child's stdin
-  let child_stdin = Arc::new(Mutex::new(child.stdin.take().unwrap()));
-
-  // Create a task that feeds the child stdin from our stdin
-  task::spawn(input_task(child_stdin.clone()));
-
-  // Wait for a terminate signal
-  signal(SignalKind::terminate()).expect("stream error").recv().await;
-  if debug {
-    match kill_command {
-      Some(kill_command) => eprintln!("SIGTERM received! Sending kill command to the child: {}", kill_command),
-      None => eprintln!("SIGTERM received! Performing a clean shutdown"),
-    }
-  }
-
-  // Grab a lock on the child_stdin process (and don't ever release it)
-  let mut child_stdin = child_stdin.lock().await;
-
-  // Optionally write the newlines and kill-command
-  if newline_before_kill {
-    child_stdin.write_all("\n".as_bytes()).await.unwrap_or_else(|e| {
-      eprintln!("Error writing kill command to child: {}", e);
-      exit(1);
-    });
-  }
-  if let Some(kill_command) = kill_command {
-    child_stdin.write_all(kill_command.as_bytes()).await.unwrap_or_else(|e| {
-      eprintln!("Error writing kill command to child: {}", e);
-      exit(1);
-    });
-  }
-  if newline_after_kill {
-    child_stdin.write_all("\n".as_bytes()).await.unwrap_or_else(|e| {
-      eprintln!("Error writing kill command to child: {}", e);
-      exit(1);
-    });
-  }
-
-  if debug {
-    eprintln!("Waiting for child process to exit...");
-  }
-
-  // Wait for child to exit
-  let status = child.await;
-  if debug {
-    match status {
-      Ok(status) => eprintln!("Child process ended with status: {}", status),
-      Err(e)     => eprintln!("An error occurred while the child was exiting: {}", e),
-    };
-  }
-
-  // Stop the process cleanly (otherwise, we'll be waiting forever on the stdin
-  // thread)
-  exit(0);
+  // supervisor::run() never calls exit() itself, so that the child it's
+  // holding is always cleanly torn down (via ChildGuard's Drop) before we
+  // actually terminate the process here
+  let code = supervisor::run(RunOpts {
+    binary,
+    binary_args,
+    debug,
+    kill_command,
+    newline_before_kill,
+    newline_after_kill,
+    shutdown_timeout,
+    init,
+    control_socket,
+    log_format,
+    log_file,
+    restart,
+    max_restarts,
+    forward_signals,
+  }).await;
+
+  exit(code);
 }
@@ -0,0 +1,113 @@
+use nix::sys::signal::Signal;
+use tokio::signal::unix::SignalKind;
+
+/// What to do when a `--forward-signal` rule's signal arrives.
+#[derive(Clone)]
+pub enum SignalAction {
+  /// Forward the same OS signal on to the child's pid.
+  Forward(Signal),
+  /// Send this line (plus a trailing newline) to the child's stdin.
+  Command(String),
+  /// Initiate the same clean shutdown SIGTERM triggers.
+  Shutdown,
+}
+
+/// Parses one `--forward-signal <sig>=<action>` value, e.g. `SIGHUP=reload`,
+/// `SIGINT=forward`, or `SIGQUIT=shutdown`.
+pub fn parse_rule(spec: &str) -> Result<(SignalKind, SignalAction), String> {
+  let (name, action) = spec.split_once('=').ok_or_else(|| {
+    format!("'{}' is not in <sig>=<action> form", spec)
+  })?;
+
+  // TERM already gets its own dedicated listener that drives the wrapper's
+  // clean-shutdown path (kill-command, --shutdown-timeout escalation).
+  // Registering a second listener for it here would race that one on every
+  // real SIGTERM, so remapping it is never meaningful - reject it instead
+  // of silently doubling up.
+  if name.strip_prefix("SIG").unwrap_or(name) == "TERM" {
+    return Err("SIGTERM is already handled by the wrapper's own clean shutdown and can't be remapped with --forward-signal".to_string());
+  }
+
+  let (kind, signal) = signal_by_name(name).ok_or_else(|| {
+    format!("unrecognized signal '{}'", name)
+  })?;
+
+  let action = match action {
+    "forward" => SignalAction::Forward(signal),
+    "shutdown" => SignalAction::Shutdown,
+    command => SignalAction::Command(command.to_string()),
+  };
+
+  Ok((kind, action))
+}
+
+/// Maps a signal name (as it'd appear in `kill -l`, e.g. "SIGHUP" or "HUP")
+/// to the `SignalKind` to listen on and the `Signal` to forward, if asked.
+///
+/// TERM is deliberately not handled here - see the check in `parse_rule`.
+fn signal_by_name(name: &str) -> Option<(SignalKind, Signal)> {
+  let name = name.strip_prefix("SIG").unwrap_or(name);
+
+  match name {
+    "HUP"  => Some((SignalKind::hangup(), Signal::SIGHUP)),
+    "INT"  => Some((SignalKind::interrupt(), Signal::SIGINT)),
+    "QUIT" => Some((SignalKind::quit(), Signal::SIGQUIT)),
+    "USR1" => Some((SignalKind::user_defined1(), Signal::SIGUSR1)),
+    "USR2" => Some((SignalKind::user_defined2(), Signal::SIGUSR2)),
+    "ALRM" => Some((SignalKind::alarm(), Signal::SIGALRM)),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_rule_forward() {
+    let (kind, action) = parse_rule("HUP=forward").unwrap();
+    assert_eq!(kind, SignalKind::hangup());
+    assert!(matches!(action, SignalAction::Forward(Signal::SIGHUP)));
+  }
+
+  #[test]
+  fn parse_rule_shutdown() {
+    let (kind, action) = parse_rule("SIGQUIT=shutdown").unwrap();
+    assert_eq!(kind, SignalKind::quit());
+    assert!(matches!(action, SignalAction::Shutdown));
+  }
+
+  #[test]
+  fn parse_rule_command() {
+    let (_, action) = parse_rule("USR1=save-game").unwrap();
+    assert!(matches!(action, SignalAction::Command(cmd) if cmd == "save-game"));
+  }
+
+  #[test]
+  fn parse_rule_accepts_sig_prefix_and_is_case_sensitive() {
+    assert!(parse_rule("SIGUSR2=forward").is_ok());
+    assert!(parse_rule("usr2=forward").is_err());
+  }
+
+  #[test]
+  fn parse_rule_rejects_malformed_spec() {
+    assert!(parse_rule("HUP").is_err());
+  }
+
+  #[test]
+  fn parse_rule_rejects_unknown_signal() {
+    assert!(parse_rule("BOGUS=forward").is_err());
+  }
+
+  #[test]
+  fn parse_rule_rejects_term() {
+    assert!(parse_rule("TERM=forward").is_err());
+    assert!(parse_rule("SIGTERM=shutdown").is_err());
+  }
+
+  #[test]
+  fn signal_by_name_is_none_for_term_and_unknown_names() {
+    assert!(signal_by_name("TERM").is_none());
+    assert!(signal_by_name("BOGUS").is_none());
+  }
+}
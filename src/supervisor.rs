@@ -0,0 +1,477 @@
+use crate::{control, init, logging};
+use crate::signals::SignalAction;
+use nix::sys::signal::{self as nix_signal, Signal};
+use nix::unistd::Pid;
+use std::ops::{Deref, DerefMut};
+use std::process::Stdio;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt};
+use tokio::process::{self, ChildStdin};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Mutex};
+use tokio::time::{sleep, Duration};
+
+/// How long to give the child after a direct SIGTERM (once --shutdown-timeout
+/// has already elapsed) before we give up and send SIGKILL.
+const SIGKILL_GRACE: Duration = Duration::from_secs(5);
+
+/// Exit code used when we had to force-kill the child because it didn't
+/// shut down cleanly within --shutdown-timeout.
+const EXIT_SHUTDOWN_TIMEOUT: i32 = 124;
+
+/// How long a child has to stay up before a subsequent crash is treated as
+/// a fresh failure rather than a continuation of the prior crash loop -
+/// without this, a server that's been healthy for days gets hit with the
+/// fully-escalated 60s backoff from its cumulative restart history instead
+/// of restarting promptly.
+const RESTART_COUNT_RESET_AFTER: Duration = Duration::from_secs(60);
+
+/// Owns the spawned child and guarantees it's killed on drop if it's still
+/// running (see `main.rs` for why every exit path here is a plain `return`
+/// rather than `std::process::exit()`, which would skip this). `reaped`
+/// gates that kill: under `--init` the reaper `waitpid(-1)`s children out
+/// of band, so by the time an old child drops, its pid may already have
+/// been recycled by the OS for something else.
+struct ChildGuard {
+  child: process::Child,
+  reaped: bool,
+}
+
+impl Deref for ChildGuard {
+  type Target = process::Child;
+  fn deref(&self) -> &process::Child { &self.child }
+}
+
+impl DerefMut for ChildGuard {
+  fn deref_mut(&mut self) -> &mut process::Child { &mut self.child }
+}
+
+impl Drop for ChildGuard {
+  fn drop(&mut self) {
+    if !self.reaped {
+      let _ = self.child.start_kill();
+    }
+  }
+}
+
+/// When to respawn the child after it exits on its own.
+#[derive(Clone, Copy, PartialEq)]
+pub enum RestartMode {
+  OnFailure,
+  Always,
+}
+
+/// Everything the supervisor needs to run (and possibly restart) the
+/// wrapped binary. Built once from the parsed commandline in `main`.
+pub struct RunOpts<'a> {
+  pub binary: &'a str,
+  pub binary_args: Vec<&'a str>,
+  pub debug: bool,
+  pub kill_command: Option<&'a str>,
+  pub newline_before_kill: bool,
+  pub newline_after_kill: bool,
+  pub shutdown_timeout: Option<u64>,
+  pub init: bool,
+  pub control_socket: Option<&'a str>,
+  pub log_format: logging::LogFormat,
+  pub log_file: Option<String>,
+  pub restart: Option<RestartMode>,
+  pub max_restarts: Option<u32>,
+  pub forward_signals: Vec<(SignalKind, SignalAction)>,
+}
+
+/// Runs `opts.binary`, supervising it for the lifetime of the wrapper, and
+/// returns the process exit code the caller should use. On SIGTERM this
+/// always does a clean shutdown; on the child exiting by itself, it
+/// respawns per `opts.restart`/`opts.max_restarts` with exponential
+/// backoff, or returns the child's own status if restarting is exhausted.
+pub async fn run(opts: RunOpts<'_>) -> i32 {
+  // The wrapper's own stdin is only read once; the control socket (if any)
+  // is only bound once. Both forward into whichever child is currently
+  // running, via this watch channel, so they survive restarts.
+  let (stdin_tx, stdin_rx) = watch::channel(None);
+  tokio::task::spawn(input_task(stdin_rx.clone()));
+  if let Some(control_socket) = opts.control_socket {
+    match control::bind_control_socket(control_socket) {
+      Ok(listener) => control::spawn_control_socket(listener, stdin_rx.clone()),
+      Err(e) => {
+        eprintln!("Unable to bind control socket {}: {}", control_socket, e);
+        return 1;
+      },
+    }
+  }
+
+  // Opened once and reused across every restart - see logging::open_tee
+  // for why re-opening it per spawn would be wrong.
+  let tee = match &opts.log_file {
+    Some(path) => match logging::open_tee(path) {
+      Ok(tee) => Some(tee),
+      Err(e) => {
+        eprintln!("Unable to open log file {}: {}", path, e);
+        return 1;
+      },
+    },
+    None => None,
+  };
+
+  // Under --init the reaper is also persistent across restarts: a fresh
+  // reaper per spawn would race the old one over who gets to waitpid() a
+  // given pid once more than one has ever been alive.
+  let mut reaper = if opts.init { Some(init::spawn()) } else { None };
+
+  let mut sigterm = signal(SignalKind::terminate()).expect("stream error");
+
+  // Each --forward-signal rule gets its own listener task for the life of
+  // the wrapper (signals aren't tied to any one child), funnelling into a
+  // single channel the per-child wait loop below can select! on.
+  let (forwarded_tx, mut forwarded_rx) = tokio::sync::mpsc::unbounded_channel();
+  for (kind, action) in &opts.forward_signals {
+    let tx = forwarded_tx.clone();
+    let action = action.clone();
+    let mut stream = signal(*kind).expect("stream error");
+    tokio::task::spawn(async move {
+      loop {
+        stream.recv().await;
+        if tx.send(action.clone()).is_err() {
+          return;
+        }
+      }
+    });
+  }
+
+  // restart_count is the --max-restarts budget (never reset); backoff_level
+  // is the current backoff delay and does reset on a healthy uptime.
+  let mut restart_count: u32 = 0;
+  let mut backoff_level: u32 = 0;
+
+  loop {
+    // kill_on_drop is disabled under --init - see ChildGuard.
+    let mut child = ChildGuard {
+      child: process::Command::new(opts.binary).args(&opts.binary_args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(!opts.init)
+        .spawn().unwrap_or_else(|e| {
+          eprintln!("Error creating process: {}", e);
+          std::process::exit(1);
+        }),
+      reaped: false,
+    };
+
+    let child_pid = child.id().expect("child has not been polled yet, so it must have a pid");
+    let spawned_at = Instant::now();
+    if let Some(reaper) = &reaper {
+      reaper.tracked_pid.store(child_pid, Ordering::SeqCst);
+    }
+
+    if opts.debug {
+      eprintln!("Running command: {} (pid {})", opts.binary, child_pid);
+    }
+
+    stdin_tx.send(Some(Arc::new(Mutex::new(child.stdin.take().unwrap())))).ok();
+    logging::spawn_output_tasks(child.stdout.take().unwrap(), child.stderr.take().unwrap(), opts.log_format, tee.clone());
+
+    enum Event {
+      Terminate,
+      Exited(io::Result<std::process::ExitStatus>),
+      Signal(SignalAction),
+    }
+
+    // Keep servicing forwarded signals against this same child until it
+    // either exits on its own or we're told to terminate.
+    let outcome = loop {
+      let event = tokio::select! {
+        _ = sigterm.recv() => Event::Terminate,
+        status = child_wait(&mut child, &mut reaper) => Event::Exited(status),
+        Some(action) = forwarded_rx.recv() => Event::Signal(action),
+      };
+
+      match event {
+        Event::Terminate => break Event::Terminate,
+        Event::Exited(status) => break Event::Exited(status),
+        Event::Signal(SignalAction::Shutdown) => break Event::Terminate,
+        Event::Signal(action) => {
+          apply_signal_action(&action, child_pid, stdin_tx.borrow().clone()).await;
+        },
+      }
+    };
+
+    match outcome {
+      Event::Terminate => {
+        return clean_shutdown(&mut child, &mut reaper, child_pid, &opts, stdin_tx.borrow().clone()).await;
+      },
+      Event::Signal(_) => unreachable!("Signal events are consumed inside the inner wait loop"),
+      Event::Exited(status) => {
+        stdin_tx.send(None).ok();
+
+        match &status {
+          Ok(status) => eprintln!("Child exited with {}", status),
+          Err(e) => eprintln!("An error occurred while the child was exiting: {}", e),
+        }
+
+        // Only the backoff level resets on a healthy uptime, not
+        // restart_count, or --max-restarts would never be reached.
+        if spawned_at.elapsed() >= RESTART_COUNT_RESET_AFTER {
+          backoff_level = 0;
+        }
+
+        let failed = !matches!(&status, Ok(s) if s.success());
+        let should_restart = match opts.restart {
+          None => false,
+          Some(RestartMode::Always) => true,
+          Some(RestartMode::OnFailure) => failed,
+        };
+        let restarts_left = opts.max_restarts.map_or(true, |max| restart_count < max);
+
+        if !should_restart || !restarts_left {
+          return status.ok().and_then(|s| s.code()).unwrap_or(1);
+        }
+
+        restart_count += 1;
+        backoff_level += 1;
+        let backoff = backoff_for(backoff_level);
+        eprintln!("Restarting in {:?} (attempt {})", backoff, restart_count);
+
+        // A SIGTERM/=shutdown during backoff exits now instead of
+        // respawning - no child is alive to run clean_shutdown against.
+        let sleep_fut = sleep(backoff);
+        tokio::pin!(sleep_fut);
+        let terminated = loop {
+          tokio::select! {
+            _ = &mut sleep_fut => break false,
+            _ = sigterm.recv() => break true,
+            Some(action) = forwarded_rx.recv() => {
+              if matches!(action, SignalAction::Shutdown) {
+                break true;
+              }
+              eprintln!("WARNING: dropping forwarded signal, no child is currently running");
+            },
+          }
+        };
+        if terminated {
+          return 0;
+        }
+      },
+    }
+  }
+}
+
+/// Carries out a single `--forward-signal` rule's action against the
+/// currently-running child. `SignalAction::Shutdown` is handled by the
+/// caller (it ends the wait loop rather than acting on the live child), so
+/// it never reaches here.
+async fn apply_signal_action(action: &SignalAction, child_pid: u32, child_stdin: Option<Arc<Mutex<ChildStdin>>>) {
+  match action {
+    SignalAction::Forward(signal) => {
+      if let Err(e) = nix_signal::kill(Pid::from_raw(child_pid as i32), *signal) {
+        eprintln!("WARNING: failed to forward signal to child pid {}: {}", child_pid, e);
+      }
+    },
+    SignalAction::Command(command) => {
+      match child_stdin {
+        Some(child_stdin) => {
+          let mut line = command.clone();
+          line.push('\n');
+          if let Err(e) = child_stdin.lock().await.write_all(line.as_bytes()).await {
+            eprintln!("WARNING: failed to send forwarded-signal command to child: {}", e);
+          }
+        },
+        None => eprintln!("WARNING: dropping forwarded-signal command, no child is currently running"),
+      }
+    },
+    SignalAction::Shutdown => unreachable!("handled by the caller before calling apply_signal_action"),
+  }
+}
+
+/// Exponential backoff between restart attempts, starting at 1s and capping
+/// at 60s so a crash-looping server doesn't spin the CPU or spam restarts.
+fn backoff_for(backoff_level: u32) -> Duration {
+  let secs = 1u64.saturating_shl(backoff_level.saturating_sub(1).min(6));
+  Duration::from_secs(secs.min(60))
+}
+
+/// Waits for `child` to exit, preferring the reaper's channel under --init,
+/// and marks it reaped on success (see `ChildGuard`).
+async fn child_wait(child: &mut ChildGuard, reaper: &mut Option<init::Reaper>) -> io::Result<std::process::ExitStatus> {
+  let status = match reaper {
+    Some(reaper) => init::wait_for_tracked_exit(reaper).await.ok_or_else(|| {
+      io::Error::new(io::ErrorKind::Other, "reaper task ended before the child's exit was observed")
+    }),
+    None => child.child.wait().await,
+  };
+  if status.is_ok() {
+    child.reaped = true;
+  }
+  status
+}
+
+async fn input_task(mut child_stdin: control::SharedStdin) {
+  let mut lines = io::BufReader::new(io::stdin()).lines();
+
+  loop {
+    let line = lines.next_line().await;
+
+    let line = match line {
+      Ok(l) => l,
+      Err(e) => {
+        eprintln!("WARNING: can't read from stdin: {}", e);
+        return;
+      },
+    };
+
+    let mut line = match line {
+      Some(l) => l,
+      None => {
+        eprintln!("WARNING: can't read from stdin: closed");
+        return;
+      },
+    };
+    line.push('\n');
+
+    let stdin = child_stdin.borrow_and_update().clone();
+    match stdin {
+      Some(stdin) => {
+        stdin.lock().await.write_all(line.as_bytes()).await.unwrap_or_else(|e| {
+          eprintln!("WARNING: unable to write to child process: {}", e);
+        });
+      },
+      None => eprintln!("WARNING: dropping stdin line, no child is currently running"),
+    }
+  }
+}
+
+// A broken pipe means the child had already exited - not a failed shutdown.
+async fn write_kill_bytes(child_stdin: &mut ChildStdin, data: &[u8]) -> Result<(), i32> {
+  match child_stdin.write_all(data).await {
+    Ok(()) => Ok(()),
+    Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+    Err(e) => {
+      eprintln!("Error writing kill command to child: {}", e);
+      Err(1)
+    },
+  }
+}
+
+/// Sends the kill-command (if any) to the current child's stdin, then waits
+/// for it to exit - escalating to SIGTERM/SIGKILL per --shutdown-timeout if
+/// it doesn't - and returns the exit code the wrapper should use.
+async fn clean_shutdown(child: &mut ChildGuard, reaper: &mut Option<init::Reaper>, child_pid: u32, opts: &RunOpts<'_>, child_stdin: Option<Arc<Mutex<ChildStdin>>>) -> i32 {
+  if opts.debug {
+    match opts.kill_command {
+      Some(kill_command) => eprintln!("SIGTERM received! Sending kill command to the child: {}", kill_command),
+      None => eprintln!("SIGTERM received! Performing a clean shutdown"),
+    }
+  }
+
+  if let Some(child_stdin) = child_stdin {
+    let mut child_stdin = child_stdin.lock().await;
+
+    if opts.newline_before_kill {
+      if let Err(code) = write_kill_bytes(&mut child_stdin, b"\n").await {
+        return code;
+      }
+    }
+    if let Some(kill_command) = opts.kill_command {
+      if let Err(code) = write_kill_bytes(&mut child_stdin, kill_command.as_bytes()).await {
+        return code;
+      }
+    }
+    if opts.newline_after_kill {
+      if let Err(code) = write_kill_bytes(&mut child_stdin, b"\n").await {
+        return code;
+      }
+    }
+  }
+
+  if opts.debug {
+    eprintln!("Waiting for child process to exit...");
+  }
+
+  match opts.shutdown_timeout {
+    Some(secs) => wait_with_timeout(child, reaper, child_pid, Duration::from_secs(secs), opts.debug).await,
+    None => {
+      let status = child_wait(child, reaper).await;
+      if opts.debug {
+        match &status {
+          Ok(status) => eprintln!("Child process ended with status: {}", status),
+          Err(e) => eprintln!("An error occurred while the child was exiting: {}", e),
+        };
+      }
+      0
+    },
+  }
+}
+
+/// Wait for `child` to exit, and if it hasn't within `timeout`, escalate: send
+/// it a direct SIGTERM, give it a short grace period, then SIGKILL it.
+/// Returns EXIT_SHUTDOWN_TIMEOUT if escalation was needed (the child failed
+/// to honour the kill-command), otherwise 0.
+async fn wait_with_timeout(child: &mut ChildGuard, reaper: &mut Option<init::Reaper>, pid: u32, timeout: Duration, debug: bool) -> i32 {
+  let status = tokio::select! {
+    status = child_wait(child, reaper) => status,
+    _ = sleep(timeout) => {
+      return escalate(child, reaper, pid, debug).await;
+    },
+  };
+
+  if debug {
+    match status {
+      Ok(status) => eprintln!("Child process ended with status: {}", status),
+      Err(e) => eprintln!("An error occurred while the child was exiting: {}", e),
+    };
+  }
+  0
+}
+
+/// The SIGTERM-then-SIGKILL escalation once --shutdown-timeout has elapsed.
+async fn escalate(child: &mut ChildGuard, reaper: &mut Option<init::Reaper>, pid: u32, debug: bool) -> i32 {
+  eprintln!("WARNING: child didn't exit within the shutdown timeout of the kill-command; sending SIGTERM");
+  if let Err(e) = nix_signal::kill(Pid::from_raw(pid as i32), Signal::SIGTERM) {
+    eprintln!("WARNING: failed to send SIGTERM to child pid {}: {}", pid, e);
+  }
+
+  let status = tokio::select! {
+    status = child_wait(child, reaper) => status,
+    _ = sleep(SIGKILL_GRACE) => {
+      eprintln!("WARNING: child still alive {:?} after SIGTERM; sending SIGKILL", SIGKILL_GRACE);
+      if let Err(e) = child.start_kill() {
+        eprintln!("WARNING: failed to send SIGKILL to child: {}", e);
+      }
+      child_wait(child, reaper).await
+    },
+  };
+
+  if debug {
+    eprintln!("Escalated shutdown finished; exiting with code {}", EXIT_SHUTDOWN_TIMEOUT);
+  }
+  if let Ok(status) = status {
+    eprintln!("Child exited with {} after forced shutdown", status);
+  }
+  EXIT_SHUTDOWN_TIMEOUT
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn backoff_for_starts_at_one_second() {
+    assert_eq!(backoff_for(1), Duration::from_secs(1));
+  }
+
+  #[test]
+  fn backoff_for_doubles_each_attempt() {
+    assert_eq!(backoff_for(2), Duration::from_secs(2));
+    assert_eq!(backoff_for(3), Duration::from_secs(4));
+    assert_eq!(backoff_for(4), Duration::from_secs(8));
+  }
+
+  #[test]
+  fn backoff_for_caps_at_sixty_seconds() {
+    assert_eq!(backoff_for(7), Duration::from_secs(60));
+    assert_eq!(backoff_for(100), Duration::from_secs(60));
+  }
+}